@@ -1,14 +1,158 @@
-use std::{cell::UnsafeCell, fmt, mem, ops};
+use std::{
+    cell::{Cell, UnsafeCell},
+    fmt, ops,
+    sync::Once,
+};
 
-/// A non-thread-safe lazy cell.
-pub struct UnsyncLazy<T, F> {
-    inner: UnsafeCell<LazyInner<T, F>>,
+/// A non-thread-safe lazy value.
+///
+/// This is a thin wrapper pairing an [`UnsyncOnceCell`] with the closure
+/// that will fill it; see that type's docs if the value to store isn't
+/// known until runtime, rather than fixed at construction.
+///
+/// Note: `T` and `F` live in separate fields here, so this type costs
+/// roughly `size_of::<T>() + size_of::<F>()`, not the `max(size_of::<T>(),
+/// size_of::<F>())` an earlier, union-based revision achieved by having the
+/// value and the closure share one slot. That sharing was deliberately
+/// dropped in favor of building `UnsyncLazy` directly on top of
+/// [`UnsyncOnceCell`]'s `get`/`set`/`take` logic rather than duplicating it
+/// in hand-rolled union/`ManuallyDrop` code. If the size difference matters
+/// for a given `T`/`F` pair, use `UnsyncOnceCell` directly and manage the
+/// closure yourself.
+///
+/// Note: an earlier revision's commit message claimed this type was made
+/// covariant in `F`. That was wrong: forcing the value mutates the stored
+/// closure through a shared `&self` (so `Deref::deref` can run it without
+/// `&mut`), and any type offering interior mutability over `F` must be
+/// invariant in `F` -- a covariant one would let a shorter-lived closure be
+/// written in through a longer-lived reference and then read back out with
+/// the longer lifetime, which is unsound. `UnsyncLazy` is, and always was,
+/// invariant in both `T` and `F`.
+pub struct UnsyncLazy<T, F = fn() -> T> {
+    cell: UnsyncOnceCell<T>,
+    init: Cell<Option<F>>,
 }
 
-enum LazyInner<T, F> {
-    Init(T),
-    Uninit(F),
-    Empty,
+/// A thread-safe lazy cell.
+///
+/// Unlike [`UnsyncLazy`], this type is `Sync` as long as `T: Sync + Send` and
+/// `F: Send`, so it can be used to initialize `static`s on first access from
+/// any number of threads. A [`Once`] guards the initialization, so only a
+/// single thread will ever run the init closure `F`; other threads accessing
+/// the value concurrently will block until initialization completes.
+///
+/// `F` must be `Send` (and `T` too, alongside its `Sync` bound) because the
+/// closure actually runs on whichever thread wins the race to call
+/// `Once::call_once` -- not necessarily the thread that constructed the
+/// `SyncLazy` -- and the resulting `T` can likewise end up being dropped by
+/// a different thread than the one that produced it.
+pub struct SyncLazy<T, F = fn() -> T> {
+    once: Once,
+    value: UnsafeCell<Option<T>>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Sync + Send, F: Send> Sync for SyncLazy<T, F> {}
+
+/// A non-thread-safe cell which can be written to at most once.
+///
+/// Unlike [`UnsyncLazy`], this isn't paired with any particular init
+/// closure: it can be filled directly with [`set`](Self::set), or lazily
+/// on demand with [`get_or_init`](Self::get_or_init), with each call site
+/// free to supply its own closure.
+pub struct UnsyncOnceCell<T> {
+    inner: UnsafeCell<Option<T>>,
+}
+
+impl<T, F> UnsyncLazy<T, F> {
+    /// Returns a reference to the value if it has already been initialized,
+    /// or `None` if it has not.
+    ///
+    /// Unlike [`Deref`](ops::Deref), this never runs the init closure.
+    pub fn get(this: &Self) -> Option<&T> {
+        this.cell.get()
+    }
+
+    /// Returns a mutable reference to the value if it has already been
+    /// initialized, or `None` if it has not.
+    ///
+    /// Unlike [`DerefMut`](ops::DerefMut), this never runs the init closure.
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        this.cell.get_mut()
+    }
+
+    /// Returns `true` if the init closure previously panicked while this
+    /// value was being forced.
+    ///
+    /// Once poisoned, all future calls to [`force`](Self::force),
+    /// [`force_mut`](Self::force_mut), or dereferencing `this` will panic.
+    pub fn is_poisoned(this: &Self) -> bool {
+        // The closure is taken out of `init` before it's called, so if
+        // `cell` is still empty and `init` is too, the only way we got here
+        // is a previous call to `force` that never made it back to fill
+        // `cell`.
+        this.cell.get().is_none() && unsafe { &*this.init.as_ptr() }.is_none()
+    }
+
+    /// Consumes this value, returning `Ok` with the already-computed value
+    /// if it was initialized, or `Err` with the init closure if it was
+    /// never forced.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `this` has been [poisoned](Self::is_poisoned) by a previous
+    /// panic during initialization.
+    pub fn into_inner(this: Self) -> Result<T, F> {
+        match this.cell.into_inner() {
+            Some(value) => Ok(value),
+            None => match this.init.into_inner() {
+                Some(f) => Err(f),
+                None => panic!("UnsyncLazy instance has previously been poisoned"),
+            },
+        }
+    }
+}
+
+impl<T, F> UnsyncLazy<T, F>
+where
+    F: FnOnce() -> T,
+{
+    /// Forces evaluation of this lazy value and returns a reference to the
+    /// result.
+    ///
+    /// This is equivalent to dereferencing `this`, but is spelled out as an
+    /// associated function so it doesn't shadow a method of the same name
+    /// on `T` reached through `Deref`.
+    pub fn force(this: &Self) -> &T {
+        if let Some(value) = this.cell.get() {
+            return value;
+        }
+        let f = this
+            .init
+            .take()
+            .unwrap_or_else(|| panic!("UnsyncLazy instance has previously been poisoned"));
+        // If `f()` panics here, `this.init` is left holding the `None` we
+        // just took, and `this.cell` is still empty, so the next call to
+        // `force` takes the `unwrap_or_else` branch above instead of
+        // silently retrying with a closure that's already been consumed.
+        let value = f();
+        // `set` can only fail if `cell` were already filled, which can't
+        // happen: `UnsyncLazy` isn't `Sync`, so nothing else could have run
+        // between the `get` above and this `set`.
+        let _ = this.cell.set(value);
+        this.cell.get().unwrap()
+    }
+
+    /// Forces evaluation of this lazy value and returns a mutable reference
+    /// to the result.
+    ///
+    /// This is equivalent to mutably dereferencing `this`, but is spelled
+    /// out as an associated function so it doesn't shadow a method of the
+    /// same name on `T` reached through `DerefMut`.
+    pub fn force_mut(this: &mut Self) -> &mut T {
+        Self::force(this);
+        this.cell.get_mut().unwrap()
+    }
 }
 
 impl<T, F> ops::Deref for UnsyncLazy<T, F>
@@ -17,18 +161,7 @@ where
 {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        loop {
-            unsafe {
-                // It's safe to access the inner data, as it will only be
-                // mutated if it does not already exist, and this type is not
-                // Sync, guarding against multiple concurrent mutations.
-                let ptr = self.inner.get();
-                if let LazyInner::Init(ref t) = &*ptr {
-                    return t;
-                }
-                (*ptr).force();
-            }
-        }
+        Self::force(self)
     }
 }
 
@@ -37,16 +170,7 @@ where
     F: FnOnce() -> T,
 {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        loop {
-            unsafe {
-                // This function is safe as we have mut access to the cell regardless.
-                let ptr = self.inner.get();
-                if let LazyInner::Init(ref mut t) = &mut *ptr {
-                    return t;
-                }
-                (*ptr).force();
-            }
-        }
+        Self::force_mut(self)
     }
 }
 
@@ -56,38 +180,264 @@ where
 {
     fn from(f: F) -> Self {
         Self {
-            inner: UnsafeCell::new(LazyInner::Uninit(f)),
+            cell: UnsyncOnceCell::new(),
+            init: Cell::new(Some(f)),
         }
     }
 }
 
 impl<T: fmt::Debug, F> fmt::Debug for UnsyncLazy<T, F> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        unsafe { (*self.inner.get()).fmt(f) }
+        match self.cell.get() {
+            Some(t) => t.fmt(f),
+            None if Self::is_poisoned(self) => fmt::Display::fmt("<poisoned>", f),
+            None => fmt::Display::fmt("<uninitialized>", f),
+        }
+    }
+}
+
+// === impl SyncLazy ===
+
+impl<T, F> SyncLazy<T, F> {
+    /// Returns a new `SyncLazy`, which will be initialized by calling `f`
+    /// once it is first dereferenced.
+    ///
+    /// This is a `const fn`, so it can be used to initialize a `static`.
+    pub const fn new(f: F) -> Self {
+        Self {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+            init: UnsafeCell::new(Some(f)),
+        }
     }
 }
 
-// === impl LazyInner ===
+impl<T, F> ops::Deref for SyncLazy<T, F>
+where
+    F: FnOnce() -> T,
+{
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.once.call_once(|| {
+            // Safety: `Once::call_once` guarantees that this closure runs to
+            // completion exactly once, and that no other thread can observe
+            // `self.init`/`self.value` until it has. Other threads calling
+            // `call_once` concurrently will block here until we're done.
+            unsafe {
+                let f = (*self.init.get())
+                    .take()
+                    .expect("SyncLazy init closure should only run once");
+                *self.value.get() = Some(f());
+            }
+        });
+        // Safety: the `call_once` above guarantees `self.value` is `Some` by
+        // the time we get here, on every thread.
+        unsafe { (*self.value.get()).as_ref().unwrap() }
+    }
+}
 
-impl<T, F> LazyInner<T, F>
+impl<T, F> From<F> for SyncLazy<T, F>
 where
     F: FnOnce() -> T,
 {
-    fn force(&mut self) {
-        *self = match mem::replace(self, LazyInner::Empty) {
-            LazyInner::Uninit(f) => LazyInner::Init(f()),
-            LazyInner::Empty => unreachable!(),
-            x => x,
+    fn from(f: F) -> Self {
+        Self::new(f)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for SyncLazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.once.is_completed() {
+            true => unsafe { &*self.value.get() }.fmt(f),
+            false => fmt::Display::fmt("<uninitialized>", f),
+        }
+    }
+}
+
+// === impl UnsyncOnceCell ===
+
+impl<T> UnsyncOnceCell<T> {
+    /// Returns a new, empty cell.
+    pub const fn new() -> Self {
+        Self {
+            inner: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns a reference to the cell's value, or `None` if it hasn't been
+    /// set yet.
+    pub fn get(&self) -> Option<&T> {
+        // Safety: we only ever hand out `&T`s once the slot is filled, and
+        // `UnsyncOnceCell` isn't `Sync`, so there's no concurrent `set`/
+        // `take` to race with this read.
+        unsafe { &*self.inner.get() }.as_ref()
+    }
+
+    /// Returns a mutable reference to the cell's value, or `None` if it
+    /// hasn't been set yet.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.inner.get_mut().as_mut()
+    }
+
+    /// Sets the cell's value, failing and returning `value` back if it was
+    /// already set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        // Safety: see `get`'s safety comment. `&T`s handed out by `get` may
+        // still be live, so the slot is only read here until it is known to
+        // be empty; only then, with no `&T` into it possible, is it written.
+        if unsafe { &*self.inner.get() }.is_some() {
+            return Err(value);
+        }
+        unsafe { *self.inner.get() = Some(value) };
+        Ok(())
+    }
+
+    /// Returns a reference to the existing value, or initializes it with
+    /// `f` if the cell is still empty.
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get() {
+            return value;
         }
+        // `UnsyncOnceCell` isn't `Sync`, so no other thread can fill the
+        // cell, but `f` itself may do so reentrantly. In that case `set`
+        // fails, the value `f` returned is dropped, and the value `f` stored
+        // is the one returned below.
+        let _ = self.set(f());
+        self.get().unwrap()
+    }
+
+    /// Takes the value out of the cell, leaving it empty.
+    pub fn take(&mut self) -> Option<T> {
+        self.inner.get_mut().take()
+    }
+
+    /// Consumes the cell, returning its value if it was set.
+    pub fn into_inner(self) -> Option<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T> Default for UnsyncOnceCell<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-impl<T: fmt::Debug, F> fmt::Debug for LazyInner<T, F> {
+impl<T: fmt::Debug> fmt::Debug for UnsyncOnceCell<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            LazyInner::Init(ref t) => t.fmt(f),
-            LazyInner::Uninit(_) => fmt::Display::fmt("<uninitialized>", f),
-            LazyInner::Empty => fmt::Display::fmt("<empty>", f),
+        match self.get() {
+            Some(t) => t.fmt(f),
+            None => fmt::Display::fmt("<uninitialized>", f),
         }
     }
 }
+
+// The crate has no `Cargo.toml` yet, so there's nowhere to add a
+// `trybuild`/`compiletest`-style `tests/ui` suite or a dev-dependency on
+// `loom` -- both belong there once one exists, to pin down the `Sync` bound
+// on `SyncLazy` and the (lack of) variance on `UnsyncLazy` at compile time
+// rather than relying on doc comments.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::thread;
+
+    #[test]
+    fn sync_lazy_single_thread_init() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let lazy = SyncLazy::new(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            42
+        });
+        assert_eq!(*lazy, 42);
+        assert_eq!(*lazy, 42);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn sync_lazy_runs_init_exactly_once_across_threads() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        let lazy = Arc::new(SyncLazy::new(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            // Give other threads a chance to reach `call_once` while this
+            // one is still running the init closure, so the test actually
+            // exercises the "other threads block" half of the contract.
+            thread::yield_now();
+            99
+        }));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let lazy = Arc::clone(&lazy);
+                thread::spawn(move || **lazy)
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 99);
+        }
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn unsync_lazy_poisons_on_init_panic() {
+        let lazy: UnsyncLazy<u32, _> = UnsyncLazy::from(|| panic!("boom"));
+        let caught = panic::catch_unwind(panic::AssertUnwindSafe(|| UnsyncLazy::force(&lazy)));
+        assert!(caught.is_err());
+        assert!(UnsyncLazy::is_poisoned(&lazy));
+
+        let caught_again = panic::catch_unwind(panic::AssertUnwindSafe(|| UnsyncLazy::force(&lazy)));
+        assert!(caught_again.is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "UnsyncLazy instance has previously been poisoned")]
+    fn unsync_lazy_into_inner_panics_when_poisoned() {
+        let lazy: UnsyncLazy<u32, _> = UnsyncLazy::from(|| panic!("boom"));
+        let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| UnsyncLazy::force(&lazy)));
+        let _ = UnsyncLazy::into_inner(lazy);
+    }
+
+    #[test]
+    fn unsync_lazy_into_inner_uninit_returns_closure() {
+        let lazy: UnsyncLazy<u32, _> = UnsyncLazy::from(|| 42);
+        match UnsyncLazy::into_inner(lazy) {
+            Err(f) => assert_eq!(f(), 42),
+            Ok(_) => panic!("lazy was never forced, should not have been Ok"),
+        }
+    }
+
+    #[test]
+    fn unsync_lazy_into_inner_forced_returns_value() {
+        let lazy: UnsyncLazy<u32> = UnsyncLazy::from((|| 42) as fn() -> u32);
+        assert_eq!(*lazy, 42);
+        match UnsyncLazy::into_inner(lazy) {
+            Ok(value) => assert_eq!(value, 42),
+            Err(_) => panic!("lazy was forced, should not have been Err"),
+        }
+    }
+
+    #[test]
+    fn once_cell_set_get_and_into_inner() {
+        let cell = UnsyncOnceCell::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+        assert_eq!(cell.into_inner(), Some(1));
+    }
+
+    #[test]
+    fn once_cell_default_is_empty() {
+        let cell: UnsyncOnceCell<u32> = UnsyncOnceCell::default();
+        assert_eq!(cell.get(), None);
+    }
+}